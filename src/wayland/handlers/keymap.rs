@@ -1,7 +1,8 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
-use crate::{state::State, wayland::protocols::keymap::delegate_keymap};
 use crate::wayland::protocols::keymap::{KeymapHandler, KeymapState};
+use crate::{state::State, wayland::protocols::keymap::delegate_keymap};
+use smithay::input::keyboard::{KeyboardHandle, Layout};
 
 impl KeymapHandler for State {
     fn keymap_state(&mut self) -> &mut KeymapState {
@@ -9,4 +10,14 @@ impl KeymapHandler for State {
     }
 }
 
+impl State {
+    /// Hook for the keyboard input pipeline: call this after `keyboard.input()`
+    /// when xkb reports that the active layout actually changed, so the new
+    /// group is pushed to just the keymap objects bound to `keyboard` instead
+    /// of polling every keymap through `KeymapState::refresh` each frame.
+    pub fn keymap_layout_changed(&mut self, keyboard: &KeyboardHandle<State>, layout: Layout) {
+        self.notify_layout_changed(keyboard, layout);
+    }
+}
+
 delegate_keymap!(State);
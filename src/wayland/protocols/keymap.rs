@@ -1,29 +1,106 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
+//! Server-side glue for the `zcosmic_keymap_*` protocol.
+//!
+//! This module depends on protocol additions that must land in the pinned
+//! `cosmic-protocols` (version 2 of the `keymap` protocol) alongside it:
+//!
+//! - `zcosmic_keymap_v1`: the `group_name`, `group_name_reset` and `locks`
+//!   events, the `set_lock` request, the `lock`/`locks` enums, and the
+//!   `EVT_GROUP_NAME_SINCE` / `EVT_LOCKS_SINCE` version sentinels.
+//! - `zcosmic_keymap_manager_v1`: the `set_xkb_config` and `set_keymap`
+//!   requests, the `keymap_format` enum, and the `bad_format`/`bad_keymap`
+//!   errors.
+//!
+//! It likewise relies on `KeyboardHandle::{set_xkb_config, set_keymap}` and the
+//! xkb `set_modifier_locked` accessor being present in the pinned smithay.
+
 use cosmic_protocols::keymap::v1::server::{
     zcosmic_keymap_manager_v1::{self, ZcosmicKeymapManagerV1},
-    zcosmic_keymap_v1::{self, ZcosmicKeymapV1},
+    zcosmic_keymap_v1::{self, Lock, Locks, ZcosmicKeymapV1},
 };
 use smithay::{
     input::{
-        keyboard::{KeyboardHandle, Layout},
+        keyboard::{xkb, KeyboardHandle, Layout, Xkb, XkbConfig},
         SeatHandler,
     },
     reexports::wayland_server::{
         Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
     },
 };
-use std::mem;
-use wayland_backend::server::{ClientId, GlobalId};
+use std::{collections::HashMap, mem, os::unix::io::OwnedFd};
+use wayland_backend::server::{ClientId, GlobalId, ObjectId};
 
 pub trait KeymapHandler {
     fn keymap_state(&mut self) -> &mut KeymapState;
+
+    /// Rebuild the keyboard's XKB keymap from the given RMLVO parameters.
+    ///
+    /// Returns `false` if the configuration could not be compiled into a
+    /// keymap, in which case the previous keymap stays active.
+    fn set_xkb_config(&mut self, keyboard: &KeyboardHandle<Self>, config: XkbConfig<'_>) -> bool
+    where
+        Self: SeatHandler + Sized,
+    {
+        keyboard.set_xkb_config(self, config).is_ok()
+    }
+
+    /// Push a layout change to the keymap objects bound to `keyboard`.
+    ///
+    /// The input pipeline calls this only when xkb reports that the active
+    /// layout actually changed, so dispatch touches just the affected objects
+    /// and never acquires the xkb lock on the refresh hot path.
+    fn notify_layout_changed(&mut self, keyboard: &KeyboardHandle<Self>, layout: Layout)
+    where
+        Self: SeatHandler + Sized,
+    {
+        for entries in self.keymap_state().keymaps.values_mut() {
+            let matches = entries.first().is_some_and(|(keymap, _, _)| {
+                keymap
+                    .data::<KeymapUserData<Self>>()
+                    .and_then(|data| data.handle.as_ref())
+                    .is_some_and(|handle| handle == keyboard)
+            });
+            if !matches {
+                continue;
+            }
+            for (keymap, last_layout, _) in entries.iter_mut() {
+                if *last_layout != Some(layout) {
+                    keymap.group(layout.0);
+                    *last_layout = Some(layout);
+                }
+            }
+        }
+    }
 }
 
+/// A keymap object together with the last layout/locks broadcast to it, so
+/// changes are only emitted on transition. `KeymapState` groups these per
+/// `wl_keyboard`.
+type KeymapEntry = (ZcosmicKeymapV1, Option<Layout>, Option<Locks>);
+
 #[derive(Debug)]
 pub struct KeymapState {
     pub global: GlobalId,
-    keymaps: Vec<(ZcosmicKeymapV1, Option<Layout>)>,
+    keymaps: HashMap<ObjectId, Vec<KeymapEntry>>,
+}
+
+/// Map the locked-modifier bitmask of an xkb state into the protocol's distinct
+/// Caps Lock / Num Lock flags.
+fn read_locks(xkb: &Xkb) -> Locks {
+    let mut locks = Locks::empty();
+    if xkb.mod_name_is_active(xkb::MOD_NAME_CAPS, xkb::STATE_MODS_LOCKED) {
+        locks |= Locks::CapsLock;
+    }
+    if xkb.mod_name_is_active(xkb::MOD_NAME_NUM, xkb::STATE_MODS_LOCKED) {
+        locks |= Locks::NumLock;
+    }
+    locks
+}
+
+/// Read the currently locked modifiers (Caps Lock, Num Lock) from an xkb state.
+fn locked_modifiers<D: SeatHandler + 'static>(handle: &KeyboardHandle<D>, state: &mut D) -> Locks {
+    handle.with_xkb_state(state, |context| read_locks(&context.xkb().lock().unwrap()))
 }
 
 impl KeymapState {
@@ -33,31 +110,47 @@ impl KeymapState {
         F: for<'a> Fn(&'a Client) -> bool + Send + Sync + 'static,
     {
         let global = dh.create_global::<D, ZcosmicKeymapManagerV1, _>(
-            1,
+            2,
             KeymapGlobalData {
                 filter: Box::new(client_filter),
             },
         );
         KeymapState {
             global,
-            keymaps: Vec::new(),
+            keymaps: HashMap::new(),
         }
     }
 
+    /// Full-sync fallback: re-derive layout and locks for every bound keymap.
+    ///
+    /// Layout changes are normally pushed through
+    /// [`KeymapHandler::notify_layout_changed`]; this path exists so freshly
+    /// bound clients and lock-modifier changes still converge.
     pub fn refresh<D>(state: &mut D)
     where
         D: SeatHandler + KeymapHandler + 'static,
     {
         let mut keymaps = mem::take(&mut state.keymap_state().keymaps);
-        for (keymap, last_layout) in &mut keymaps {
-            if let Some(data) = keymap.data::<KeymapUserData<D>>() {
-                if let Some(handle) = &data.handle {
-                    let active_layout = handle.with_xkb_state(state, |context| {
-                        context.xkb().lock().unwrap().active_layout()
-                    });
-                    if *last_layout != Some(active_layout) {
-                        keymap.group(active_layout.0);
-                        *last_layout = Some(active_layout);
+        for entries in keymaps.values_mut() {
+            for (keymap, last_layout, last_locks) in entries.iter_mut() {
+                if let Some(data) = keymap.data::<KeymapUserData<D>>() {
+                    if let Some(handle) = data.handle.clone() {
+                        // Read the active layout and locked modifiers under a
+                        // single xkb lock acquisition per keymap.
+                        let (active_layout, locks) = handle.with_xkb_state(state, |context| {
+                            let xkb = context.xkb().lock().unwrap();
+                            (xkb.active_layout(), read_locks(&xkb))
+                        });
+                        if *last_layout != Some(active_layout) {
+                            keymap.group(active_layout.0);
+                            *last_layout = Some(active_layout);
+                        }
+                        if *last_locks != Some(locks) {
+                            if keymap.version() >= zcosmic_keymap_v1::EVT_LOCKS_SINCE {
+                                keymap.locks(locks);
+                            }
+                            *last_locks = Some(locks);
+                        }
                     }
                 }
             }
@@ -112,16 +205,125 @@ where
         match request {
             zcosmic_keymap_manager_v1::Request::GetKeymap { keymap, keyboard } => {
                 let handle = KeyboardHandle::<D>::from_resource(&keyboard);
-                let active_layout = handle.as_ref().map(|handle| {
-                    handle.with_xkb_state(state, |context| {
-                        context.xkb().lock().unwrap().active_layout()
+                let (active_layout, layout_names) = handle
+                    .as_ref()
+                    .map(|handle| {
+                        handle.with_xkb_state(state, |context| {
+                            let xkb = context.xkb().lock().unwrap();
+                            let names = xkb
+                                .layouts()
+                                .map(|layout| xkb.layout_name(layout).to_string())
+                                .collect::<Vec<_>>();
+                            (xkb.active_layout(), names)
+                        })
                     })
-                });
+                    .map_or((None, Vec::new()), |(layout, names)| (Some(layout), names));
+                let locks = handle
+                    .as_ref()
+                    .map(|handle| locked_modifiers(handle, state));
                 let keymap = data_init.init(keymap, KeymapUserData { handle });
+                if keymap.version() >= zcosmic_keymap_v1::EVT_GROUP_NAME_SINCE {
+                    for name in layout_names {
+                        keymap.group_name(name);
+                    }
+                }
                 if let Some(layout) = active_layout {
                     keymap.group(layout.0);
                 }
-                state.keymap_state().keymaps.push((keymap, active_layout));
+                if let Some(locks) = locks {
+                    if keymap.version() >= zcosmic_keymap_v1::EVT_LOCKS_SINCE {
+                        keymap.locks(locks);
+                    }
+                }
+                state
+                    .keymap_state()
+                    .keymaps
+                    .entry(keyboard.id())
+                    .or_default()
+                    .push((keymap, active_layout, locks));
+            }
+            zcosmic_keymap_manager_v1::Request::SetXkbConfig {
+                keyboard,
+                rules,
+                model,
+                layout,
+                variant,
+                options,
+            } => {
+                if let Some(handle) = KeyboardHandle::<D>::from_resource(&keyboard) {
+                    let config = XkbConfig {
+                        rules: &rules,
+                        model: &model,
+                        layout: &layout,
+                        variant: &variant,
+                        options: (!options.is_empty()).then_some(options),
+                    };
+                    if !state.set_xkb_config(&handle, config) {
+                        _resource.post_error(
+                            zcosmic_keymap_manager_v1::Error::BadKeymap,
+                            "failed to compile the requested xkb configuration",
+                        );
+                        return;
+                    }
+                    // Reset the cached layout/locks for the affected keyboard and
+                    // re-send the new layout names, so the change-gated refresh
+                    // re-broadcasts the active group and locks even when the
+                    // active group index is unchanged.
+                    invalidate_cache::<D>(state, &handle);
+                    rebroadcast_layout_names::<D>(state, &handle);
+                    KeymapState::refresh(state);
+                }
+            }
+            zcosmic_keymap_manager_v1::Request::SetKeymap {
+                keyboard,
+                format,
+                fd,
+                size,
+            } => {
+                if !matches!(
+                    format.into_result(),
+                    Ok(zcosmic_keymap_manager_v1::KeymapFormat::XkbV1)
+                ) {
+                    _resource.post_error(
+                        zcosmic_keymap_manager_v1::Error::BadFormat,
+                        "only xkb_v1 keymaps are supported",
+                    );
+                    return;
+                }
+                let Some(handle) = KeyboardHandle::<D>::from_resource(&keyboard) else {
+                    return;
+                };
+                let text = match read_keymap_from_fd(fd, size as usize) {
+                    Some(text) => text,
+                    None => {
+                        _resource.post_error(
+                            zcosmic_keymap_manager_v1::Error::BadKeymap,
+                            "failed to read keymap from fd",
+                        );
+                        return;
+                    }
+                };
+                let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+                let keymap = xkb::Keymap::new_from_string(
+                    &context,
+                    text,
+                    xkb::KEYMAP_FORMAT_TEXT_V1,
+                    xkb::KEYMAP_COMPILE_NO_FLAGS,
+                );
+                let Some(keymap) = keymap else {
+                    _resource.post_error(
+                        zcosmic_keymap_manager_v1::Error::BadKeymap,
+                        "failed to compile keymap",
+                    );
+                    return;
+                };
+                if handle.set_keymap(state, keymap).is_ok() {
+                    // Drop the cached layout so the next refresh re-broadcasts
+                    // the active group of the freshly installed keymap.
+                    invalidate_cache::<D>(state, &handle);
+                    rebroadcast_layout_names::<D>(state, &handle);
+                    KeymapState::refresh(state);
+                }
             }
             zcosmic_keymap_manager_v1::Request::Destroy => {}
             _ => unreachable!(),
@@ -129,6 +331,95 @@ where
     }
 }
 
+/// Re-emit the ordered `group_name` list to every keymap object bound to
+/// `handle`. Called after a runtime keymap rebuild so clients that learned the
+/// index→name mapping at bind time pick up the new layout set.
+fn rebroadcast_layout_names<D>(state: &mut D, handle: &KeyboardHandle<D>)
+where
+    D: SeatHandler + KeymapHandler + 'static,
+{
+    let names = handle.with_xkb_state(state, |context| {
+        let xkb = context.xkb().lock().unwrap();
+        xkb.layouts()
+            .map(|layout| xkb.layout_name(layout).to_string())
+            .collect::<Vec<_>>()
+    });
+    for entries in state.keymap_state().keymaps.values_mut() {
+        for (keymap, _, _) in entries.iter_mut() {
+            let matches = keymap
+                .data::<KeymapUserData<D>>()
+                .and_then(|data| data.handle.as_ref())
+                .is_some_and(|h| h == handle);
+            if !matches || keymap.version() < zcosmic_keymap_v1::EVT_GROUP_NAME_SINCE {
+                continue;
+            }
+            // Clear the previously advertised list first: the rebuilt keymap has
+            // a fresh `0..n` index space, so appending to the old names would
+            // leave the `group` indices pointing at stale entries.
+            keymap.group_name_reset();
+            for name in &names {
+                keymap.group_name(name.clone());
+            }
+        }
+    }
+}
+
+/// Drop the cached layout/locks of every keymap object bound to `handle` so the
+/// next [`KeymapState::refresh`] re-broadcasts the active group after a runtime
+/// keymap rebuild, even when the group index happens to be unchanged.
+fn invalidate_cache<D>(state: &mut D, handle: &KeyboardHandle<D>)
+where
+    D: SeatHandler + KeymapHandler + 'static,
+{
+    for entries in state.keymap_state().keymaps.values_mut() {
+        for (keymap, last_layout, last_locks) in entries.iter_mut() {
+            if keymap
+                .data::<KeymapUserData<D>>()
+                .and_then(|data| data.handle.as_ref())
+                .is_some_and(|h| h == handle)
+            {
+                *last_layout = None;
+                *last_locks = None;
+            }
+        }
+    }
+}
+
+/// Read a NUL-terminated xkb keymap string out of a shared-memory fd, the same
+/// transport `wl_keyboard` uses to hand keymaps to clients, but inbound.
+fn read_keymap_from_fd(fd: OwnedFd, size: usize) -> Option<String> {
+    use rustix::mm::{mmap, munmap, MapFlags, ProtFlags};
+
+    if size == 0 {
+        return None;
+    }
+    // Reject a `size` larger than the backing file: mapping and reading past the
+    // real end of the fd would fault with SIGBUS and take the compositor down.
+    let stat = rustix::fs::fstat(&fd).ok()?;
+    if (size as u64) > stat.st_size as u64 {
+        return None;
+    }
+    let ptr = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            size,
+            ProtFlags::READ,
+            MapFlags::PRIVATE,
+            &fd,
+            0,
+        )
+        .ok()?
+    };
+    let bytes = unsafe { std::slice::from_raw_parts(ptr as *const u8, size) };
+    // The buffer is NUL-terminated, mirroring the wl_keyboard keymap format.
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(size);
+    let text = std::str::from_utf8(&bytes[..end]).ok().map(str::to_owned);
+    unsafe {
+        let _ = munmap(ptr, size);
+    }
+    text
+}
+
 #[doc(hidden)]
 pub struct KeymapUserData<D: SeatHandler> {
     handle: Option<KeyboardHandle<D>>,
@@ -152,10 +443,26 @@ where
     ) {
         match request {
             zcosmic_keymap_v1::Request::SetGroup { group } => {
-                if let Some(handle) = data.handle.as_ref() {
+                if let Some(handle) = data.handle.clone() {
                     handle.with_xkb_state(state, |mut context| {
                         context.set_layout(Layout(group));
                     });
+                    // Push the new group to every keymap bound to this keyboard
+                    // through the same entry point the input pipeline uses.
+                    state.notify_layout_changed(&handle, Layout(group));
+                }
+            }
+            zcosmic_keymap_v1::Request::SetLock { lock, locked } => {
+                if let Some(handle) = data.handle.clone() {
+                    let mod_name = match lock.into_result() {
+                        Ok(Lock::CapsLock) => xkb::MOD_NAME_CAPS,
+                        Ok(Lock::NumLock) => xkb::MOD_NAME_NUM,
+                        _ => return,
+                    };
+                    handle.with_xkb_state(state, |mut context| {
+                        context.set_modifier_locked(mod_name, locked != 0);
+                    });
+                    KeymapState::refresh(state);
                 }
             }
             zcosmic_keymap_v1::Request::Destroy => {}
@@ -170,9 +477,12 @@ where
         _data: &KeymapUserData<D>,
     ) {
         let keymaps = &mut state.keymap_state().keymaps;
-        if let Some(idx) = keymaps.iter().position(|(x, _)| x == keymap) {
-            keymaps.remove(idx);
-        }
+        keymaps.retain(|_, entries| {
+            if let Some(idx) = entries.iter().position(|(x, _, _)| x == keymap) {
+                entries.remove(idx);
+            }
+            !entries.is_empty()
+        });
     }
 }
 